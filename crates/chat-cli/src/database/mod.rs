@@ -1,7 +1,10 @@
 pub mod settings;
 
 use std::ops::Deref;
-use std::path::Path;
+use std::path::{
+    Path,
+    PathBuf,
+};
 use std::str::FromStr;
 use std::sync::PoisonError;
 
@@ -61,6 +64,7 @@ const IDC_REGION_KEY: &str = "auth.idc.region";
 // We include this key to remove for backwards compatibility
 const CUSTOMIZATION_STATE_KEY: &str = "api.selectedCustomization";
 const PROFILE_MIGRATION_KEY: &str = "profile.Migrated";
+const CONVERSATION_KEYS_CANONICALIZED_KEY: &str = "conversations.keysCanonicalized";
 
 const MIGRATIONS: &[Migration] = migrations![
     "000_migration_table",
@@ -192,7 +196,8 @@ impl Database {
                     pool: Pool::builder().build(SqliteConnectionManager::memory()).unwrap(),
                     settings: Settings::new().await?,
                 }
-                .migrate();
+                .migrate()
+                .map(Self::canonicalize_conversation_keys);
             },
             false => database_path()?,
         };
@@ -225,7 +230,8 @@ impl Database {
             settings: Settings::new().await?,
         }
         .migrate()
-        .map_err(|e| DbOpenError(e.to_string()))?)
+        .map_err(|e| DbOpenError(e.to_string()))?
+        .canonicalize_conversation_keys())
     }
 
     /// Get all entries for dumping the persistent application state.
@@ -331,13 +337,22 @@ impl Database {
         &mut self,
         path: impl AsRef<Path>,
     ) -> Result<Option<ConversationState>, DatabaseError> {
-        // We would need to encode this to support non utf8 paths.
-        let path = match path.as_ref().to_str() {
-            Some(path) => path,
+        let path = path.as_ref();
+        let key = match conversation_key(path) {
+            Some(key) => key,
             None => return Ok(None),
         };
 
-        self.get_json_entry(Table::Conversations, path)
+        if let Some(state) = self.get_json_entry(Table::Conversations, &key)? {
+            return Ok(Some(state));
+        }
+
+        // Fall back to the literal path for conversations saved before keys were canonicalized
+        // that could not be re-keyed, e.g. because their directory was missing at the time.
+        match path.to_str() {
+            Some(literal_key) if literal_key != key => self.get_json_entry(Table::Conversations, literal_key),
+            _ => Ok(None),
+        }
     }
 
     /// Set a chat conversation given a path to the conversation.
@@ -346,13 +361,12 @@ impl Database {
         path: impl AsRef<Path>,
         state: &ConversationState,
     ) -> Result<usize, DatabaseError> {
-        // We would need to encode this to support non utf8 paths.
-        let path = match path.as_ref().to_str() {
-            Some(path) => path,
+        let key = match conversation_key(path) {
+            Some(key) => key,
             None => return Ok(0),
         };
 
-        self.set_json_entry(Table::Conversations, path, state)
+        self.set_json_entry(Table::Conversations, key, state)
     }
 
     pub async fn get_secret(&self, key: &str) -> Result<Option<Secret>, DatabaseError> {
@@ -373,6 +387,66 @@ impl Database {
 
     // Private functions. Do not expose.
 
+    /// Copies conversations saved before keys were canonicalized to their canonical key, so they
+    /// can still be found from the canonical form of their directory. Runs once per database.
+    ///
+    /// The original rows are left in place so older clients sharing the database can still find
+    /// them.
+    ///
+    /// Failures are logged rather than returned so that a bad entry never prevents the database
+    /// from opening.
+    fn canonicalize_conversation_keys(self) -> Self {
+        if let Err(err) = self.try_canonicalize_conversation_keys() {
+            error!(?err, "Failed to canonicalize conversation keys");
+        }
+        self
+    }
+
+    fn try_canonicalize_conversation_keys(&self) -> Result<(), DatabaseError> {
+        if self
+            .get_entry::<bool>(Table::State, CONVERSATION_KEYS_CANONICALIZED_KEY)?
+            .unwrap_or(false)
+        {
+            return Ok(());
+        }
+
+        let mut conn = self.pool.get()?;
+        let transaction = conn.transaction()?;
+
+        let keys = transaction
+            .prepare(&format!("SELECT key FROM {}", Table::Conversations))?
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+
+        for key in keys {
+            let canonical_key = match conversation_key(&key) {
+                Some(canonical_key) if canonical_key != key => canonical_key,
+                _ => continue,
+            };
+
+            // If a conversation is already stored under the canonical key, it is the one chat has
+            // been resuming, so it must not be overwritten.
+            let copied = transaction.execute(
+                &format!(
+                    "INSERT OR IGNORE INTO {table} (key, value) SELECT ?1, value FROM {table} WHERE key = ?2",
+                    table = Table::Conversations
+                ),
+                params![canonical_key, key],
+            )?;
+            if copied > 0 {
+                info!(from = %key, to = %canonical_key, "Canonicalized conversation key");
+            }
+        }
+
+        transaction.execute(
+            &format!("INSERT OR REPLACE INTO {} (key, value) VALUES (?1, ?2)", Table::State),
+            params![CONVERSATION_KEYS_CANONICALIZED_KEY, true],
+        )?;
+        transaction.commit()?;
+
+        Ok(())
+    }
+
     fn migrate(self) -> Result<Self, DatabaseError> {
         let mut conn = self.pool.get()?;
         let transaction = conn.transaction()?;
@@ -465,6 +539,22 @@ impl Database {
     }
 }
 
+/// Returns the key a conversation for `path` is stored under.
+///
+/// Symlinks and `.`/`..` components are resolved so that logically identical directories map to the
+/// same conversation. Paths that cannot be resolved (e.g. because they no longer exist) are used as
+/// given.
+///
+/// [dunce::canonicalize] is used so that Windows keys keep their plain `C:\...` form instead of the
+/// extended-length `\\?\C:\...` form, which older clients would not recognize.
+fn conversation_key(path: impl AsRef<Path>) -> Option<String> {
+    let path = path.as_ref();
+    let path = dunce::canonicalize(path).unwrap_or_else(|_| PathBuf::from(path));
+
+    // We would need to encode this to support non utf8 paths.
+    path.to_str().map(str::to_owned)
+}
+
 fn max_migration_version<C: Deref<Target = Connection>>(conn: &C) -> Option<i64> {
     let mut stmt = conn.prepare("SELECT MAX(version) FROM migrations").ok()?;
     stmt.query_row([], |row| row.get(0)).ok()
@@ -587,6 +677,142 @@ mod tests {
         assert!(db.get_entry::<bool>(Table::State, "bool").unwrap().is_some());
     }
 
+    #[cfg(unix)]
+    #[test]
+    fn test_conversation_key_resolves_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let real = dir.path().join("proj");
+        let link = dir.path().join("link");
+        std::fs::create_dir(&real).unwrap();
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+
+        let key = conversation_key(&real).unwrap();
+        assert_eq!(conversation_key(&link).unwrap(), key);
+        assert_eq!(conversation_key(real.join("..").join("proj")).unwrap(), key);
+
+        // Paths that cannot be resolved are used as given.
+        assert_eq!(conversation_key("/does/not/exist").unwrap(), "/does/not/exist");
+    }
+
+    #[cfg(unix)]
+    fn test_conversation(conversation_id: &str) -> ConversationState {
+        serde_json::from_value(serde_json::json!({
+            "conversation_id": conversation_id,
+            "history": [],
+            "valid_history_range": [0, 0],
+            "transcript": [],
+            "tools": {},
+        }))
+        .unwrap()
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_conversation_by_symlinked_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let real = dir.path().join("proj");
+        let link = dir.path().join("link");
+        std::fs::create_dir(&real).unwrap();
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+
+        let mut db = Database::new().await.unwrap();
+        db.set_conversation_by_path(&link, &test_conversation("symlinked"))
+            .unwrap();
+
+        let state = db.get_conversation_by_path(&real).unwrap().unwrap();
+        assert_eq!(state.conversation_id(), "symlinked");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_conversation_by_path_falls_back_to_legacy_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let real = dir.path().join("proj");
+        let link = dir.path().join("link");
+        std::fs::create_dir(&real).unwrap();
+        std::os::unix::fs::symlink(&real, &link).unwrap();
+
+        // A legacy entry the one-time re-key never copied, e.g. because the directory was missing.
+        let mut db = Database::new().await.unwrap();
+        db.set_json_entry(
+            Table::Conversations,
+            link.to_str().unwrap(),
+            test_conversation("legacy"),
+        )
+        .unwrap();
+
+        let state = db.get_conversation_by_path(&link).unwrap().unwrap();
+        assert_eq!(state.conversation_id(), "legacy");
+    }
+
+    #[cfg(windows)]
+    #[tokio::test]
+    async fn test_canonicalize_conversation_keys_keeps_plain_windows_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dunce::canonicalize(dir.path()).unwrap();
+        let key = path.to_str().unwrap();
+        assert!(!key.starts_with(r"\\?\"));
+        assert_eq!(conversation_key(&path).unwrap(), key);
+
+        let db = Database::new().await.unwrap();
+        db.delete_entry(Table::State, CONVERSATION_KEYS_CANONICALIZED_KEY)
+            .unwrap();
+        db.set_entry(Table::Conversations, key, "legacy").unwrap();
+
+        let db = db.canonicalize_conversation_keys();
+        let conversations = db.all_entries(Table::Conversations).unwrap();
+        assert_eq!(conversations.len(), 1);
+        assert_eq!(conversations.get(key), Some(&Value::String("legacy".into())));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_canonicalize_conversation_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let real = dir.path().join("proj");
+        let link = dir.path().join("link");
+        let other_real = dir.path().join("other");
+        let other_link = dir.path().join("other_link");
+        for (real, link) in [(&real, &link), (&other_real, &other_link)] {
+            std::fs::create_dir(real).unwrap();
+            std::os::unix::fs::symlink(real, link).unwrap();
+        }
+        let real_key = conversation_key(&real).unwrap();
+        let other_real_key = conversation_key(&other_real).unwrap();
+
+        let db = Database::new().await.unwrap();
+        db.delete_entry(Table::State, CONVERSATION_KEYS_CANONICALIZED_KEY)
+            .unwrap();
+        db.set_entry(Table::Conversations, link.to_str().unwrap(), "legacy")
+            .unwrap();
+        db.set_entry(Table::Conversations, other_link.to_str().unwrap(), "legacy")
+            .unwrap();
+        db.set_entry(Table::Conversations, &other_real_key, "current").unwrap();
+
+        let db = db.canonicalize_conversation_keys();
+        let conversations = db.all_entries(Table::Conversations).unwrap();
+
+        // Legacy entries are copied under their canonical key and kept for older clients.
+        assert_eq!(conversations.get(&real_key), Some(&Value::String("legacy".into())));
+        assert_eq!(
+            conversations.get(link.to_str().unwrap()),
+            Some(&Value::String("legacy".into()))
+        );
+
+        // Existing canonical entries are never overwritten.
+        assert_eq!(
+            conversations.get(&other_real_key),
+            Some(&Value::String("current".into()))
+        );
+        assert!(conversations.contains_key(other_link.to_str().unwrap()));
+
+        assert_eq!(
+            db.get_entry::<bool>(Table::State, CONVERSATION_KEYS_CANONICALIZED_KEY)
+                .unwrap(),
+            Some(true)
+        );
+    }
+
     #[tokio::test]
     #[ignore = "not on ci"]
     async fn test_set_password() {